@@ -1,5 +1,7 @@
 extern crate curl;
+extern crate semver;
 extern crate serialize;
+extern crate time;
 
 use std::fmt;
 use std::io::{mod, fs, MemReader, MemWriter, File};
@@ -8,23 +10,33 @@ use std::io::util::ChainedReader;
 use std::result;
 
 use curl::http;
-use serialize::json;
+use serialize::{Decodable, json};
 
 pub struct Registry {
     host: String,
-    token: String,
+    token: Option<String>,
     handle: http::Handle,
 }
 
 pub type Result<T> = result::Result<T, Error>;
 
+/// Whether a request should send along the registry's auth token. Read-only
+/// operations like `search` work against anonymous registries, so only
+/// operations that mutate state require `Authorized`.
+pub enum Auth {
+    Authorized,
+    Anonymous,
+}
+
 pub enum Error {
     CurlError(curl::ErrCode),
-    NotOkResponse(http::Response),
+    NotOkResponse(uint, http::Response),
     NonUtf8Body,
     ApiErrors(Vec<String>),
     Unauthorized,
     IoError(io::IoError),
+    JsonDecodeError(String),
+    InvalidMetadata(String),
 }
 
 #[deriving(Encodable)]
@@ -38,11 +50,22 @@ pub struct NewCrate {
     pub documentation: Option<String>,
     pub homepage: Option<String>,
     pub readme: Option<String>,
+    pub readme_file: Option<String>,
     pub keywords: Vec<String>,
+    pub categories: Vec<String>,
     pub license: Option<String>,
+    pub license_file: Option<String>,
     pub repository: Option<String>,
 }
 
+/// The registry only accepts a handful of keywords and categories per
+/// crate; these mirror the limits enforced server-side so that `validate`
+/// can reject an obviously bad upload before a tarball is ever sent.
+const MAX_KEYWORDS: uint = 5;
+const MAX_KEYWORD_LENGTH: uint = 20;
+const MAX_CATEGORIES: uint = 5;
+const MAX_CATEGORY_LENGTH: uint = 20;
+
 #[deriving(Encodable)]
 pub struct NewCrateDependency {
     pub optional: bool,
@@ -53,17 +76,65 @@ pub struct NewCrateDependency {
     pub target: Option<String>,
 }
 
+#[deriving(Decodable)]
+pub struct Crate {
+    pub name: String,
+    pub description: Option<String>,
+    pub max_version: String,
+}
+
 #[deriving(Decodable)] struct R { ok: bool }
 #[deriving(Decodable)] struct ApiErrorList { errors: Vec<ApiError> }
 #[deriving(Decodable)] struct ApiError { detail: String }
 #[deriving(Encodable)] struct OwnersReq<'a> { users: &'a [&'a str] }
+#[deriving(Decodable)] struct Crates { crates: Vec<Crate>, meta: TotalCrates }
+#[deriving(Decodable)] struct TotalCrates { total: u32 }
+
+impl NewCrate {
+    /// Checks this crate's metadata against the registry's publish
+    /// constraints before any network request is made, so that obviously
+    /// invalid uploads are rejected without round-tripping a tarball.
+    pub fn validate(&self) -> Result<()> {
+        if self.license.is_some() == self.license_file.is_some() {
+            return Err(InvalidMetadata(format!(
+                "exactly one of `license` or `license_file` must be specified")));
+        }
+        if semver::Version::parse(self.vers.as_slice()).is_err() {
+            return Err(InvalidMetadata(format!(
+                "`{}` is not a valid semver version", self.vers)));
+        }
+        if self.keywords.len() > MAX_KEYWORDS {
+            return Err(InvalidMetadata(format!(
+                "a crate can only have up to {} keywords", MAX_KEYWORDS)));
+        }
+        for keyword in self.keywords.iter() {
+            if keyword.len() > MAX_KEYWORD_LENGTH {
+                return Err(InvalidMetadata(format!(
+                    "keyword `{}` is longer than {} characters",
+                    keyword, MAX_KEYWORD_LENGTH)));
+            }
+        }
+        if self.categories.len() > MAX_CATEGORIES {
+            return Err(InvalidMetadata(format!(
+                "a crate can only have up to {} categories", MAX_CATEGORIES)));
+        }
+        for category in self.categories.iter() {
+            if category.len() > MAX_CATEGORY_LENGTH {
+                return Err(InvalidMetadata(format!(
+                    "category `{}` is longer than {} characters",
+                    category, MAX_CATEGORY_LENGTH)));
+            }
+        }
+        Ok(())
+    }
+}
 
 impl Registry {
-    pub fn new(host: String, token: String) -> Registry {
+    pub fn new(host: String, token: Option<String>) -> Registry {
         Registry::new_handle(host, token, http::Handle::new())
     }
 
-    pub fn new_handle(host: String, token: String,
+    pub fn new_handle(host: String, token: Option<String>,
                       handle: http::Handle) -> Registry {
         Registry {
             host: host,
@@ -74,21 +145,33 @@ impl Registry {
 
     pub fn add_owners(&mut self, krate: &str, owners: &[&str]) -> Result<()> {
         let body = json::encode(&OwnersReq { users: owners });
-        let body = try!(self.put(format!("/crates/{}/owners", krate),
-                                 body.as_bytes()));
-        assert!(json::decode::<R>(body.as_slice()).unwrap().ok);
-        Ok(())
+        let body = try!(self.put(format!("/crates/{}/owners", encode_path(krate)),
+                                 body.as_bytes(), Authorized));
+        check_ok(try!(decode::<R>(body.as_slice())))
     }
 
     pub fn remove_owners(&mut self, krate: &str, owners: &[&str]) -> Result<()> {
         let body = json::encode(&OwnersReq { users: owners });
-        let body = try!(self.delete(format!("/crates/{}/owners", krate),
-                                    Some(body.as_bytes())));
-        assert!(json::decode::<R>(body.as_slice()).unwrap().ok);
-        Ok(())
+        let body = try!(self.delete(format!("/crates/{}/owners", encode_path(krate)),
+                                    Some(body.as_bytes()), Authorized));
+        check_ok(try!(decode::<R>(body.as_slice())))
     }
 
     pub fn publish(&mut self, krate: &NewCrate, tarball: &Path) -> Result<()> {
+        try!(self.publish_with_progress(krate, tarball, |_, _| {}));
+        Ok(())
+    }
+
+    /// Like `publish`, but `progress` is called periodically during the
+    /// upload with the number of bytes sent so far and the total size of
+    /// the request, so that a caller can draw a progress bar. Returns the
+    /// wall-clock time the upload request took, in nanoseconds, so callers
+    /// can report throughput.
+    pub fn publish_with_progress(&mut self,
+                                 krate: &NewCrate,
+                                 tarball: &Path,
+                                 mut progress: |uint, uint|) -> Result<u64> {
+        try!(krate.validate());
         let json = json::encode(krate);
         // Prepare the body. The format of the upload request is:
         //
@@ -110,43 +193,95 @@ impl Registry {
                                                box tarball as Box<Reader>].into_iter());
 
         let url = format!("{}/api/v1/crates/new", self.host);
+        let token = match self.token {
+            Some(ref s) => s.as_slice(),
+            None => return Err(Unauthorized),
+        };
+        let started = time::precise_time_ns();
         let response = handle(self.handle.put(url, &mut body)
                                          .content_length(size)
-                                         .header("Authorization",
-                                                 self.token.as_slice())
+                                         .header("Authorization", token)
                                          .header("Accept", "application/json")
+                                         .progress(|_, _, total, sofar| {
+                                             progress(sofar, total);
+                                             true
+                                         })
                                          .exec());
+        let elapsed = time::precise_time_ns() - started;
         let _body = try!(response);
-        Ok(())
+        Ok(elapsed)
     }
 
     pub fn yank(&mut self, krate: &str, version: &str) -> Result<()> {
-        let body = try!(self.delete(format!("/crates/{}/{}/yank", krate, version),
-                                    None));
-        assert!(json::decode::<R>(body.as_slice()).unwrap().ok);
-        Ok(())
+        let body = try!(self.delete(format!("/crates/{}/{}/yank",
+                                            encode_path(krate), encode_path(version)),
+                                    None, Authorized));
+        check_ok(try!(decode::<R>(body.as_slice())))
     }
 
     pub fn unyank(&mut self, krate: &str, version: &str) -> Result<()> {
-        let body = try!(self.put(format!("/crates/{}/{}/unyank", krate, version),
-                                 []));
-        assert!(json::decode::<R>(body.as_slice()).unwrap().ok);
-        Ok(())
+        let body = try!(self.put(format!("/crates/{}/{}/unyank",
+                                         encode_path(krate), encode_path(version)),
+                                 [], Authorized));
+        check_ok(try!(decode::<R>(body.as_slice())))
+    }
+
+    pub fn search(&mut self, query: &str, limit: u32) -> Result<(Vec<Crate>, u32)> {
+        let body = try!(self.get(format!("/crates?q={}&per_page={}",
+                                         encode_path(query), limit),
+                                 Anonymous));
+        let crates = try!(decode::<Crates>(body.as_slice()));
+        Ok((crates.crates, crates.meta.total))
     }
 
-    fn put(&mut self, path: String, b: &[u8]) -> Result<String> {
-        handle(self.handle.put(format!("{}/api/v1{}", self.host, path), b)
-                          .header("Authorization", self.token.as_slice())
-                          .header("Accept", "application/json")
-                          .content_type("application/json")
-                          .exec())
+    fn get(&mut self, path: String, authorized: Auth) -> Result<String> {
+        let mut req = self.handle.get(format!("{}/api/v1{}", self.host, path))
+                                 .header("Accept", "application/json");
+        match authorized {
+            Authorized => {
+                let token = match self.token {
+                    Some(ref s) => s.as_slice(),
+                    None => return Err(Unauthorized),
+                };
+                req = req.header("Authorization", token);
+            }
+            Anonymous => {}
+        }
+        handle(req.exec())
+    }
+
+    fn put(&mut self, path: String, b: &[u8], authorized: Auth) -> Result<String> {
+        let mut req = self.handle.put(format!("{}/api/v1{}", self.host, path), b)
+                                 .header("Accept", "application/json")
+                                 .content_type("application/json");
+        match authorized {
+            Authorized => {
+                let token = match self.token {
+                    Some(ref s) => s.as_slice(),
+                    None => return Err(Unauthorized),
+                };
+                req = req.header("Authorization", token);
+            }
+            Anonymous => {}
+        }
+        handle(req.exec())
     }
 
-    fn delete(&mut self, path: String, b: Option<&[u8]>) -> Result<String> {
+    fn delete(&mut self, path: String, b: Option<&[u8]>,
+             authorized: Auth) -> Result<String> {
         let mut req = self.handle.delete(format!("{}/api/v1{}", self.host, path))
-                                 .header("Authorization", self.token.as_slice())
                                  .header("Accept", "application/json")
                                  .content_type("application/json");
+        match authorized {
+            Authorized => {
+                let token = match self.token {
+                    Some(ref s) => s.as_slice(),
+                    None => return Err(Unauthorized),
+                };
+                req = req.header("Authorization", token);
+            }
+            Anonymous => {}
+        }
         match b {
             Some(b) => req = req.body(b),
             None => {}
@@ -155,14 +290,46 @@ impl Registry {
     }
 }
 
+/// Percent-encodes a single path segment so that crate names and versions
+/// containing characters outside the unreserved set (e.g. the `+` in a
+/// semver build metadata tag) survive being spliced into a URL path.
+fn encode_path(component: &str) -> String {
+    let mut encoded = String::new();
+    for byte in component.bytes() {
+        match byte {
+            b'A'...b'Z' | b'a'...b'z' | b'0'...b'9' | b'-' | b'_' | b'.' | b'~' => {
+                encoded.push(byte as char);
+            }
+            _ => encoded.push_str(format!("%{:02X}", byte).as_slice()),
+        }
+    }
+    encoded
+}
+
+fn decode<T: Decodable<json::Decoder, json::DecoderError>>(body: &str) -> Result<T> {
+    json::decode(body).map_err(|e| JsonDecodeError(e.to_string()))
+}
+
+/// Turns a decoded `{"ok": ...}` response into an `Error` when the registry
+/// reports failure without any accompanying error detail.
+fn check_ok(response: R) -> Result<()> {
+    if response.ok {
+        Ok(())
+    } else {
+        Err(ApiErrors(vec!["server reported failure but returned \
+                             no detail".to_string()]))
+    }
+}
+
 fn handle(response: result::Result<http::Response, curl::ErrCode>)
           -> Result<String> {
     let response = try!(response.map_err(CurlError));
-    match response.get_code() {
+    let code = response.get_code();
+    match code {
         0 => {} // file upload url sometimes
         200 => {}
         403 => return Err(Unauthorized),
-        _ => return Err(NotOkResponse(response))
+        _ => return Err(NotOkResponse(code, response))
     }
 
     let body = match String::from_utf8(response.move_body()) {
@@ -184,14 +351,16 @@ impl fmt::Show for Error {
         match *self {
             NonUtf8Body => write!(f, "reponse body was not utf-8"),
             CurlError(ref err) => write!(f, "http error: {}", err),
-            NotOkResponse(ref resp) => {
-                write!(f, "failed to get a 200 OK response: {}", resp)
+            NotOkResponse(code, ref resp) => {
+                write!(f, "failed to get a 200 OK response, got {}: {}", code, resp)
             }
             ApiErrors(ref errs) => {
                 write!(f, "api errors: {}", errs.connect(", "))
             }
             Unauthorized => write!(f, "unauthorized API access"),
             IoError(ref e) => write!(f, "io error: {}", e),
+            JsonDecodeError(ref e) => write!(f, "failed to decode json response: {}", e),
+            InvalidMetadata(ref e) => write!(f, "invalid crate metadata: {}", e),
         }
     }
 }